@@ -1,5 +1,7 @@
 //! Sample capsule for Tock tutorial. It handles an alarm to
-//! sample the humidity sensor
+//! sample the humidity, temperature, ambient light, and 9DOF sensors
+//! in turn, optionally smoothing the humidity readings or warning when
+//! they cross a configured threshold band.
 
 #![forbid(unsafe_code)]
 #![no_std]
@@ -9,30 +11,265 @@
 #[macro_use(debug)]
 extern crate kernel;
 
-use kernel::hil::sensors::{HumidityDriver, HumidityClient};
-use kernel::hil::time::{self, Alarm, Frequency};
+use core::cell::Cell;
+use kernel::hil::sensors::{
+    AmbientLight, AmbientLightClient, HumidityClient, HumidityDriver, NineDof, NineDofClient,
+    TemperatureClient, TemperatureDriver,
+};
+use kernel::hil::time::{self, Alarm, Frequency, Time};
+
+/// Number of recent readings kept by the moving-average smoothing filter.
+const WINDOW_SIZE: usize = 8;
+
+/// Which sensor the capsule will read on the next alarm `fired()`.
+#[derive(Clone, Copy, PartialEq)]
+enum SensorPhase {
+    Humidity,
+    Temperature,
+    Light,
+    NineDof,
+}
 
 pub struct Tutorial<'a, A: Alarm + 'a> {
     alarm: &'a A,
     humidity: &'a HumidityDriver,
+    temperature: &'a TemperatureDriver,
+    light: &'a AmbientLight,
+    ninedof: &'a NineDof,
+    interval_ms: Cell<u32>,
+    phase: Cell<SensorPhase>,
+    thresholds: Option<(usize, usize)>,
+    alerting: Cell<bool>,
+    smoothing: bool,
+    window: Cell<[usize; WINDOW_SIZE]>,
+    write_idx: Cell<usize>,
+    count: Cell<usize>,
+    sum: Cell<usize>,
+    running: Cell<bool>,
 }
 
 impl<'a, A: Alarm> Tutorial<'a, A> {
-    pub fn new(alarm: &'a A, humidity: &'a HumidityDriver) -> Tutorial<'a, A> {
+    pub fn new(
+        alarm: &'a A,
+        humidity: &'a HumidityDriver,
+        temperature: &'a TemperatureDriver,
+        light: &'a AmbientLight,
+        ninedof: &'a NineDof,
+    ) -> Tutorial<'a, A> {
         Tutorial {
             alarm: alarm,
             humidity: humidity,
+            temperature: temperature,
+            light: light,
+            ninedof: ninedof,
+            interval_ms: Cell::new(1000),
+            phase: Cell::new(SensorPhase::Humidity),
+            thresholds: None,
+            alerting: Cell::new(false),
+            smoothing: false,
+            window: Cell::new([0; WINDOW_SIZE]),
+            write_idx: Cell::new(0),
+            count: Cell::new(0),
+            sum: Cell::new(0),
+            running: Cell::new(false),
+        }
+    }
+
+    /// Like `new`, but only emits a `debug!` for the humidity channel when
+    /// a sample crosses out of the `[low, high]` band, rather than on every
+    /// reading. `low` and `high` implement hysteresis: an alert is raised
+    /// once a sample rises above `high`, and cleared once a sample falls
+    /// below `low`, so jitter within the band is ignored. The other three
+    /// sensors in the rotation are unaffected and keep logging every
+    /// sample.
+    pub fn new_with_thresholds(
+        alarm: &'a A,
+        humidity: &'a HumidityDriver,
+        temperature: &'a TemperatureDriver,
+        light: &'a AmbientLight,
+        ninedof: &'a NineDof,
+        low: usize,
+        high: usize,
+    ) -> Tutorial<'a, A> {
+        Tutorial {
+            thresholds: Some((low, high)),
+            ..Tutorial::new(alarm, humidity, temperature, light, ninedof)
+        }
+    }
+
+    /// Like `new`, but reports the mean of the last `WINDOW_SIZE` humidity
+    /// readings instead of the raw sample, to smooth out sensor noise. The
+    /// other three sensors in the rotation are unaffected.
+    pub fn new_with_smoothing(
+        alarm: &'a A,
+        humidity: &'a HumidityDriver,
+        temperature: &'a TemperatureDriver,
+        light: &'a AmbientLight,
+        ninedof: &'a NineDof,
+    ) -> Tutorial<'a, A> {
+        Tutorial {
+            smoothing: true,
+            ..Tutorial::new(alarm, humidity, temperature, light, ninedof)
         }
     }
 
     pub fn start(&self) {
+        self.running.set(true);
+        let now = self.alarm.now();
+        self.schedule_next(now);
+    }
+
+    /// Halts sampling. The alarm is disabled immediately, and any callback
+    /// already in flight will see `is_running` false and not reschedule.
+    pub fn stop(&self) {
+        self.running.set(false);
+        self.alarm.disable();
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.get()
+    }
+
+    /// Updates the sampling period. If the capsule is currently running,
+    /// the new period takes effect the next time the alarm is rearmed.
+    pub fn set_period_ms(&self, ms: u32) {
+        self.interval_ms.set(ms);
+    }
+
+    /// Arms the alarm to fire `interval_ms` after `now`, wrapping correctly
+    /// if the addition overflows the timer's width.
+    fn schedule_next(&self, now: u32) {
+        let tics = self.ms_to_tics(self.interval_ms.get());
+        self.alarm.set_alarm(now.wrapping_add(tics));
+    }
+
+    fn ms_to_tics(&self, ms: u32) -> u32 {
+        let freq = <A::Frequency>::frequency();
+        // Multiply before dividing so periods that aren't an exact multiple
+        // of 1000 Hz (e.g. a 32.768 kHz alarm source) don't drift.
+        ((freq as u64 * ms as u64) / 1000) as u32
+    }
+
+    /// Advances `phase` to the next sensor in the rotation and returns the
+    /// phase that was just advanced past, i.e. the one to sample now.
+    fn next_phase(&self) -> SensorPhase {
+        let current = self.phase.get();
+        self.phase.set(match current {
+            SensorPhase::Humidity => SensorPhase::Temperature,
+            SensorPhase::Temperature => SensorPhase::Light,
+            SensorPhase::Light => SensorPhase::NineDof,
+            SensorPhase::NineDof => SensorPhase::Humidity,
+        });
+        current
+    }
+
+    /// Overwrites the oldest slot in the ring buffer with `value` and
+    /// returns the mean of all samples collected so far, dividing by the
+    /// actual count while the window is still warming up.
+    fn smoothed_average(&self, value: usize) -> usize {
+        let mut window = self.window.get();
+        let idx = self.write_idx.get();
+        let count = self.count.get();
+
+        let new_sum = if count < WINDOW_SIZE {
+            self.sum.get() + value
+        } else {
+            self.sum.get() - window[idx] + value
+        };
+        window[idx] = value;
+
+        self.window.set(window);
+        self.write_idx.set((idx + 1) % WINDOW_SIZE);
+        self.sum.set(new_sum);
+        let new_count = if count < WINDOW_SIZE { count + 1 } else { WINDOW_SIZE };
+        self.count.set(new_count);
+
+        new_sum / new_count
     }
 }
 
 impl<'a, A: Alarm> time::Client for Tutorial<'a, A> {
-    fn fired(&self) {}
+    fn fired(&self) {
+        if !self.running.get() {
+            return;
+        }
+        match self.next_phase() {
+            SensorPhase::Humidity => {
+                let _ = self.humidity.read_humidity();
+            }
+            SensorPhase::Temperature => {
+                let _ = self.temperature.read_temperature();
+            }
+            SensorPhase::Light => {
+                let _ = self.light.read_light_intensity();
+            }
+            SensorPhase::NineDof => {
+                let _ = self.ninedof.read_accelerometer();
+            }
+        }
+    }
 }
 
 impl<'a, A: Alarm> HumidityClient for Tutorial<'a, A> {
-    fn callback(&self, humidity: usize) {}
+    fn callback(&self, humidity: usize) {
+        if !self.running.get() {
+            return;
+        }
+        if self.smoothing {
+            let average = self.smoothed_average(humidity);
+            debug!("Humidity (smoothed): {}", average);
+        } else {
+            match self.thresholds {
+                None => debug!("Humidity: {}", humidity),
+                Some((low, high)) => {
+                    let was_alerting = self.alerting.get();
+                    if !was_alerting && humidity > high {
+                        self.alerting.set(true);
+                        debug!("Humidity alert: {} above threshold {}", humidity, high);
+                    } else if was_alerting && humidity < low {
+                        self.alerting.set(false);
+                        debug!(
+                            "Humidity alert cleared: {} below threshold {}",
+                            humidity, low
+                        );
+                    }
+                }
+            }
+        }
+        let now = self.alarm.now();
+        self.schedule_next(now);
+    }
+}
+
+impl<'a, A: Alarm> TemperatureClient for Tutorial<'a, A> {
+    fn callback(&self, temperature: usize) {
+        if !self.running.get() {
+            return;
+        }
+        debug!("Temperature: {}", temperature);
+        let now = self.alarm.now();
+        self.schedule_next(now);
+    }
+}
+
+impl<'a, A: Alarm> AmbientLightClient for Tutorial<'a, A> {
+    fn callback(&self, lux: usize) {
+        if !self.running.get() {
+            return;
+        }
+        debug!("Light: {}", lux);
+        let now = self.alarm.now();
+        self.schedule_next(now);
+    }
+}
+
+impl<'a, A: Alarm> NineDofClient for Tutorial<'a, A> {
+    fn callback(&self, x: usize, y: usize, z: usize) {
+        if !self.running.get() {
+            return;
+        }
+        debug!("Acceleration: ({}, {}, {})", x, y, z);
+        let now = self.alarm.now();
+        self.schedule_next(now);
+    }
 }